@@ -19,6 +19,7 @@ impl Size {
   }
 }
 
+#[deriving(Clone, PartialEq)]
 pub struct Cell(pub u16, pub u16);
 
 impl Cell {
@@ -79,6 +80,72 @@ impl Iterator<Cell> for CellIterator {
   }
 }
 
+// Maps a cell position to its index in a row-major buffer of the given
+// size, matching the traversal order CellIterator walks in.
+fn pos_to_index(Cell(row, col): Cell, Size(_, cols): Size) -> uint {
+  row as uint * cols as uint + col as uint
+}
+
+/*
+ * Returns how many terminal columns `c` occupies, following the usual
+ * East-Asian-width/wcwidth rules: 0 for combining/zero-width marks, 2 for
+ * wide characters (CJK ideographs, Hangul, most emoji), 1 otherwise.
+ */
+pub fn char_width(c: char) -> u8 {
+  let cp = c as u32;
+  match cp {
+    0x0300..0x036F | 0x200B..0x200F | 0xFE00..0xFE0F => 0,
+    0x1100..0x115F | 0x2E80..0xA4CF | 0xAC00..0xD7A3 |
+    0xF900..0xFAFF | 0xFE30..0xFE4F | 0xFF00..0xFF60 |
+    0xFFE0..0xFFE6 | 0x1F300..0x1FFFF => 2,
+    _ => 1,
+  }
+}
+
+/*
+ * A single grid slot in the screen's back/front buffers: the glyph that
+ * occupies it plus the colors it was (or will be) drawn with. A wide
+ * glyph occupies two adjacent cells; the second is marked `continuation`
+ * so flushing knows not to emit anything for it.
+ */
+#[deriving(Clone, PartialEq)]
+struct ScreenCell {
+  ch: char,
+  fg: color::Color,
+  bg: color::Color,
+  attrs: attr::Attributes,
+  continuation: bool,
+}
+
+impl ScreenCell {
+  fn blank() -> ScreenCell {
+    ScreenCell { ch: ' ', fg: color::White, bg: color::Black,
+                 attrs: attr::Attributes::empty(), continuation: false }
+  }
+}
+
+// The shape the software cursor is drawn with.
+#[deriving(Clone, PartialEq)]
+pub enum CursorStyle {
+  Block,
+  Underline,
+  Beam,
+}
+
+/*
+ * How the cursor's colors are chosen: either a fixed fg/bg pair, or by
+ * swapping the underlying cell's own colors (falling back to an explicit
+ * pair when those are too close to give any contrast).
+ */
+#[deriving(Clone, PartialEq)]
+pub enum CursorColor {
+  Fixed(color::Color, color::Color),
+  Inverse,
+}
+
+// Glyph used to draw the Beam cursor style.
+static BEAM_GLYPH: char = '│';
+
 /*
  * Screen is the output surface. You can put characters within its borders and
  * clear it again. Go nuts!
@@ -86,6 +153,14 @@ impl Iterator<Cell> for CellIterator {
 pub struct Screen {
   size: Size,
   terminal: Terminal,
+  theme: color::Theme,
+  // `front` mirrors what's actually on the terminal, `back` is what `put`
+  // writes into; `flush` diffs the two and only emits the difference.
+  front: Vec<ScreenCell>,
+  back: Vec<ScreenCell>,
+  cursor_position: Option<Cell>,
+  cursor_style: CursorStyle,
+  cursor_color: CursorColor,
 }
 
 impl Drop for Screen {
@@ -104,7 +179,11 @@ impl Screen {
         terminal.enable_altscreen();
         terminal.hide_cursor();
         terminal.clear();
-        Ok(Screen { size: Size(0, 0), terminal: terminal })
+        Ok(Screen { size: Size(0, 0), terminal: terminal,
+                    theme: color::Theme::default(),
+                    front: Vec::new(), back: Vec::new(),
+                    cursor_position: None, cursor_style: Block,
+                    cursor_color: Inverse })
       })
   }
 
@@ -113,31 +192,190 @@ impl Screen {
       None               => return false,
       Some(current_size) => {
         let size_changed = current_size != self.size;
-        self.size = current_size;
+        if size_changed {
+          self.size = current_size;
+          self.resize_buffers();
+        }
         return size_changed;
       }
     }
   }
 
+  fn resize_buffers(&mut self) {
+    let Size(rows, cols) = self.size;
+    let num_cells = rows as uint * cols as uint;
+    self.front = Vec::from_fn(num_cells, |_| ScreenCell::blank());
+    self.back = Vec::from_fn(num_cells, |_| ScreenCell::blank());
+  }
+
   pub fn size(&self) -> Size {
     self.size
   }
 
   pub fn clear(&mut self) {
     self.terminal.clear();
+    for cell in self.front.iter_mut() { *cell = ScreenCell::blank(); }
+    for cell in self.back.iter_mut() { *cell = ScreenCell::blank(); }
   }
 
-  pub fn put(&mut self, position: Cell, character: char,
-             fg: color::Color, bg: color::Color) {
-    position.within(self.size).map(|Cell(row, col)| {
-      self.terminal.set_cursor_position(row, col);
-      self.terminal.set_fg(fg);
-      self.terminal.set_bg(bg);
-      self.terminal.put(character);
+  pub fn put(&mut self, position: Cell, character: char, fg: color::Color,
+             bg: color::Color, attrs: attr::Attributes) {
+    let width = char_width(character);
+    if width == 0 { return; }  // combining marks don't occupy a cell of their own
+    let Size(_, cols) = self.size;
+    let Cell(row, col) = position;
+    if width == 2 && col + 1 >= cols {
+      return;  // would straddle the right border, refuse to place it
+    }
+    position.within(self.size).map(|pos| {
+      let index = pos_to_index(pos, self.size);
+      self.back[index] = ScreenCell { ch: character, fg: fg.clone(), bg: bg.clone(),
+                                       attrs: attrs.clone(), continuation: false };
+      if width == 2 {
+        let cont_index = pos_to_index(Cell(row, col + 1), self.size);
+        self.back[cont_index] = ScreenCell { ch: ' ', fg: fg, bg: bg, attrs: attrs,
+                                              continuation: true };
+      }
     });
   }
 
+  /*
+   * Swaps in a theme loaded from a color scheme file. Named colors are
+   * resolved against the current theme when cells are flushed, so cells
+   * already on the terminal keep their old colors until they are redrawn.
+   */
+  pub fn set_theme(&mut self, theme: color::Theme) {
+    self.theme = theme;
+  }
+
+  // Places the software cursor at `position`, drawn in `style` on the
+  // next `flush`. The hardware cursor stays hidden (see `setup`); this is
+  // what actually shows the caret.
+  pub fn set_cursor(&mut self, position: Cell, style: CursorStyle) {
+    self.cursor_position = Some(position);
+    self.cursor_style = style;
+  }
+
+  // Configures how the cursor is colored: a fixed fg/bg pair, or
+  // `Inverse` to swap whatever the underlying cell's own colors are.
+  pub fn set_cursor_color(&mut self, color: CursorColor) {
+    self.cursor_color = color;
+  }
+
+  // Applies `cursor_style`/`cursor_color` on top of the cell the cursor
+  // currently sits over, for compositing into the flushed buffer. The
+  // Underline style only adds the underline attribute and leaves the
+  // cell's colors as they are.
+  fn composite_cursor(&self, cell: ScreenCell) -> ScreenCell {
+    let mut cell = cell;
+    match self.cursor_style {
+      Underline => { cell.attrs = cell.attrs | attr::UNDERLINE; }
+      Block      => { cell = self.apply_cursor_color(cell); }
+      Beam       => {
+        cell = self.apply_cursor_color(cell);
+        cell.ch = BEAM_GLYPH;
+      }
+    }
+    cell
+  }
+
+  fn apply_cursor_color(&self, cell: ScreenCell) -> ScreenCell {
+    let mut cell = cell;
+    match self.cursor_color {
+      Fixed(ref fg, ref bg) => {
+        cell.fg = fg.clone();
+        cell.bg = bg.clone();
+      }
+      Inverse => {
+        let (fg, bg) = (cell.bg.clone(), cell.fg.clone());
+        if self.colors_too_close(fg.clone(), bg.clone()) {
+          cell.fg = color::Black;
+          cell.bg = color::White;
+        } else {
+          cell.fg = fg;
+          cell.bg = bg;
+        }
+      }
+    }
+    cell
+  }
+
+  // True if `a` and `b` would resolve to colors too close to tell apart,
+  // i.e. swapping them wouldn't give the cursor any visible contrast.
+  fn colors_too_close(&self, a: color::Color, b: color::Color) -> bool {
+    let a = self.theme.resolve(a);
+    let b = self.theme.resolve(b);
+    if a == b { return true; }
+    match (a, b) {
+      (color::Rgb(r1, g1, b1), color::Rgb(r2, g2, b2)) => {
+        let distance = (r1 as int - r2 as int).abs() +
+                        (g1 as int - g2 as int).abs() +
+                        (b1 as int - b2 as int).abs();
+        distance < 32
+      }
+      _ => false,
+    }
+  }
+
+  /*
+   * Diffs the back buffer against what was last flushed and emits output
+   * for the cells that changed, coalescing adjacent dirty cells into a
+   * single run so the cursor only has to be repositioned once per run
+   * instead of once per cell. The cursor, if set, is then composited over
+   * its cell and drawn there. The front buffer keeps the composited cell
+   * while the back buffer keeps the plain one, so the cursor's cell is
+   * drawn twice on every flush (plain, then with the cursor), even when
+   * nothing else changed.
+   */
   pub fn flush(&mut self) {
+    let Size(rows, cols) = self.size;
+    for row in range(0, rows) {
+      let mut col = 0u16;
+      while col < cols {
+        let index = pos_to_index(Cell(row, col), self.size);
+        if self.back[index] == self.front[index] {
+          col += 1;
+          continue;
+        }
+        self.terminal.set_cursor_position(row, col);
+        while col < cols {
+          let index = pos_to_index(Cell(row, col), self.size);
+          if self.back[index] == self.front[index] { break; }
+          let cell = self.back[index].clone();
+          if cell.continuation {
+            // the terminal already advanced over this column when we
+            // wrote the wide glyph that owns it; don't double it up
+            self.front[index] = cell;
+            col += 1;
+            continue;
+          }
+          self.terminal.set_attrs(cell.attrs.clone());
+          self.terminal.set_fg(self.theme.resolve(cell.fg.clone()));
+          self.terminal.set_bg(self.theme.resolve(cell.bg.clone()));
+          self.terminal.put(cell.ch);
+          self.front[index] = cell;
+          col += 1;
+        }
+      }
+    }
+    match self.cursor_position {
+      Some(position) => match position.within(self.size) {
+        Some(Cell(row, col)) => {
+          let index = pos_to_index(Cell(row, col), self.size);
+          let composited = self.composite_cursor(self.back[index].clone());
+          if composited != self.front[index] {
+            self.terminal.set_cursor_position(row, col);
+            self.terminal.set_attrs(composited.attrs.clone());
+            self.terminal.set_fg(self.theme.resolve(composited.fg.clone()));
+            self.terminal.set_bg(self.theme.resolve(composited.bg.clone()));
+            self.terminal.put(composited.ch);
+            self.front[index] = composited;
+          }
+        }
+        None => (),
+      },
+      None => (),
+    }
     self.terminal.flush();
   }
 }
@@ -148,19 +386,56 @@ impl Screen {
  */
 struct Terminal {
   terminal: Box<term::Terminal<term::WriterWrapper> + Send>,
+  attrs: attr::Attributes,
 }
 
 impl Terminal {
   pub fn new() -> Option<Terminal> {
-    term::stdout().map(|terminal| Terminal { terminal: terminal })
+    term::stdout().map(|terminal|
+      Terminal { terminal: terminal, attrs: attr::Attributes::empty() })
+  }
+
+  /*
+   * Applies the given attribute set, emitting the SGR codes it newly
+   * requires. Because SGR has no "turn off just bold" code, a change in
+   * attributes resets and reapplies from scratch; the currently-applied
+   * set is tracked so that an unchanged attrs value is a no-op and costs
+   * nothing. A reset also clears the current fg/bg, so callers must
+   * reapply color after calling this.
+   */
+  pub fn set_attrs(&mut self, attrs: attr::Attributes) {
+    if attrs == self.attrs { return; }
+    (write!(self.terminal, "\x1B[0m")).unwrap();
+    if attrs.contains(attr::BOLD) {
+      (write!(self.terminal, "\x1B[1m")).unwrap();
+    }
+    if attrs.contains(attr::ITALIC) {
+      (write!(self.terminal, "\x1B[3m")).unwrap();
+    }
+    if attrs.contains(attr::UNDERLINE) {
+      (write!(self.terminal, "\x1B[4m")).unwrap();
+    }
+    if attrs.contains(attr::REVERSE) {
+      (write!(self.terminal, "\x1B[7m")).unwrap();
+    }
+    if attrs.contains(attr::STRIKETHROUGH) {
+      (write!(self.terminal, "\x1B[9m")).unwrap();
+    }
+    self.attrs = attrs;
   }
 
   pub fn set_fg(&mut self, fg: color::Color) {
-    self.terminal.fg(fg.to_term_color()).unwrap();
+    match fg.to_term_color() {
+      Some(term_color) => self.terminal.fg(term_color).unwrap(),
+      None              => (write!(self.terminal, "{}", fg.to_direct_sgr(38))).unwrap(),
+    }
   }
 
   pub fn set_bg(&mut self, bg: color::Color) {
-    self.terminal.bg(bg.to_term_color()).unwrap();
+    match bg.to_term_color() {
+      Some(term_color) => self.terminal.bg(term_color).unwrap(),
+      None              => (write!(self.terminal, "{}", bg.to_direct_sgr(48))).unwrap(),
+    }
   }
 
   pub fn clear(&mut self) {
@@ -204,6 +479,10 @@ impl Terminal {
 pub mod color {
   extern crate term;
 
+  use std::io::{BufferedReader, File, IoResult};
+  use std::num::from_str_radix;
+
+  #[deriving(Clone, PartialEq)]
   pub enum Color {
     Black,
     Red,
@@ -221,30 +500,191 @@ pub mod color {
     BrightMagenta,
     BrightCyan,
     BrightWhite,
+    // A 256-color palette index.
+    Indexed(u8),
+    // A direct 24-bit color, bypassing the terminal's palette entirely.
+    Rgb(u8, u8, u8),
   }
 
   impl Color {
-    pub fn to_term_color(&self) -> term::color::Color {
+    // Returns None for Indexed/Rgb, which the `term` crate can't express
+    // and which the caller must instead emit as a direct SGR sequence.
+    pub fn to_term_color(&self) -> Option<term::color::Color> {
       match *self {
-        Black         => term::color::BLACK,
-        Red           => term::color::RED,
-        Green         => term::color::GREEN,
-        Yellow        => term::color::YELLOW,
-        Blue          => term::color::BLUE,
-        Magenta       => term::color::MAGENTA,
-        Cyan          => term::color::CYAN,
-        White         => term::color::WHITE,
-        BrightBlack   => term::color::BRIGHT_BLACK,
-        BrightRed     => term::color::BRIGHT_RED,
-        BrightGreen   => term::color::BRIGHT_GREEN,
-        BrightYellow  => term::color::BRIGHT_YELLOW,
-        BrightBlue    => term::color::BRIGHT_BLUE,
-        BrightMagenta => term::color::BRIGHT_MAGENTA,
-        BrightCyan    => term::color::BRIGHT_CYAN,
-        BrightWhite   => term::color::BRIGHT_WHITE,
+        Black         => Some(term::color::BLACK),
+        Red           => Some(term::color::RED),
+        Green         => Some(term::color::GREEN),
+        Yellow        => Some(term::color::YELLOW),
+        Blue          => Some(term::color::BLUE),
+        Magenta       => Some(term::color::MAGENTA),
+        Cyan          => Some(term::color::CYAN),
+        White         => Some(term::color::WHITE),
+        BrightBlack   => Some(term::color::BRIGHT_BLACK),
+        BrightRed     => Some(term::color::BRIGHT_RED),
+        BrightGreen   => Some(term::color::BRIGHT_GREEN),
+        BrightYellow  => Some(term::color::BRIGHT_YELLOW),
+        BrightBlue    => Some(term::color::BRIGHT_BLUE),
+        BrightMagenta => Some(term::color::BRIGHT_MAGENTA),
+        BrightCyan    => Some(term::color::BRIGHT_CYAN),
+        BrightWhite   => Some(term::color::BRIGHT_WHITE),
+        Indexed(..) | Rgb(..) => None,
+      }
+    }
+
+    // Renders the direct-color SGR sequence for this color. `mode` is 38
+    // for foreground, 48 for background, per the ISO 8613-6 convention.
+    pub fn to_direct_sgr(&self, mode: u8) -> String {
+      match *self {
+        Rgb(r, g, b) => format!("\x1B[{};2;{};{};{}m", mode, r, g, b),
+        Indexed(i)   => format!("\x1B[{};5;{}m", mode, i),
+        _            => String::new(),
+      }
+    }
+  }
+
+  /*
+   * Maps the 16 named color slots plus a primary background/foreground
+   * pair to concrete colors, so a theme file can override what the named
+   * colors actually look like (Solarized, Tomorrow Night, etc) with RGB
+   * values. The default theme maps each named color to itself.
+   */
+  #[deriving(Clone)]
+  pub struct Theme {
+    pub foreground: Color,
+    pub background: Color,
+    slots: [Color, ..16],
+  }
+
+  impl Theme {
+    pub fn default() -> Theme {
+      Theme {
+        foreground: White,
+        background: Black,
+        slots: [Black, Red, Green, Yellow, Blue, Magenta, Cyan, White,
+                BrightBlack, BrightRed, BrightGreen, BrightYellow,
+                BrightBlue, BrightMagenta, BrightCyan, BrightWhite],
+      }
+    }
+
+    /*
+     * Loads a theme from a config file of "name = 0xRRGGBB" lines, one per
+     * slot. Blank lines and lines starting with '#' are skipped; unknown
+     * slot names or unparsable values are ignored rather than failing the
+     * whole load, so a theme file can be edited without ceremony.
+     */
+    pub fn load(path: &Path) -> IoResult<Theme> {
+      let mut theme = Theme::default();
+      let mut reader = BufferedReader::new(try!(File::open(path)));
+      for line in reader.lines() {
+        let line = try!(line);
+        let line = line.as_slice().trim();
+        if line.len() == 0 || line.starts_with("#") { continue; }
+        let mut parts = line.splitn('=', 1);
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        match parse_hex_color(value) {
+          Some(color) => theme.set_slot(name, color),
+          None        => (),
+        }
+      }
+      Ok(theme)
+    }
+
+    fn set_slot(&mut self, name: &str, color: Color) {
+      match name {
+        "foreground"     => self.foreground = color,
+        "background"     => self.background = color,
+        "black"          => self.slots[0] = color,
+        "red"            => self.slots[1] = color,
+        "green"          => self.slots[2] = color,
+        "yellow"         => self.slots[3] = color,
+        "blue"           => self.slots[4] = color,
+        "magenta"        => self.slots[5] = color,
+        "cyan"           => self.slots[6] = color,
+        "white"          => self.slots[7] = color,
+        "bright_black"   => self.slots[8] = color,
+        "bright_red"     => self.slots[9] = color,
+        "bright_green"   => self.slots[10] = color,
+        "bright_yellow"  => self.slots[11] = color,
+        "bright_blue"    => self.slots[12] = color,
+        "bright_magenta" => self.slots[13] = color,
+        "bright_cyan"    => self.slots[14] = color,
+        "bright_white"   => self.slots[15] = color,
+        _                => (),
+      }
+    }
+
+    // Resolves a named color against this theme's palette. Indexed and
+    // Rgb colors already carry their own value and pass through untouched.
+    pub fn resolve(&self, color: Color) -> Color {
+      match color {
+        Black         => self.slots[0].clone(),
+        Red           => self.slots[1].clone(),
+        Green         => self.slots[2].clone(),
+        Yellow        => self.slots[3].clone(),
+        Blue          => self.slots[4].clone(),
+        Magenta       => self.slots[5].clone(),
+        Cyan          => self.slots[6].clone(),
+        White         => self.slots[7].clone(),
+        BrightBlack   => self.slots[8].clone(),
+        BrightRed     => self.slots[9].clone(),
+        BrightGreen   => self.slots[10].clone(),
+        BrightYellow  => self.slots[11].clone(),
+        BrightBlue    => self.slots[12].clone(),
+        BrightMagenta => self.slots[13].clone(),
+        BrightCyan    => self.slots[14].clone(),
+        BrightWhite   => self.slots[15].clone(),
+        other         => other,
       }
     }
   }
+
+  // Parses a "0xRRGGBB" or "RRGGBB" hex literal into an Rgb color.
+  fn parse_hex_color(value: &str) -> Option<Color> {
+    let hex = if value.starts_with("0x") { value.slice_from(2) } else { value };
+    if hex.len() != 6 || !hex.bytes().all(|b| b < 128) { return None; }
+    let r: Option<u8> = from_str_radix(hex.slice(0, 2), 16);
+    let g: Option<u8> = from_str_radix(hex.slice(2, 4), 16);
+    let b: Option<u8> = from_str_radix(hex.slice(4, 6), 16);
+    match (r, g, b) {
+      (Some(r), Some(g), Some(b)) => Some(Rgb(r, g, b)),
+      _                           => None,
+    }
+  }
+}
+
+/*
+ * Text attributes carried alongside a cell's colors, e.g. for emphasizing
+ * syntax highlighting, search matches, and status lines.
+ */
+#[allow(dead_code)]  // not all attributes are used yet
+pub mod attr {
+  #[deriving(Clone, PartialEq)]
+  pub struct Attributes {
+    bits: u8,
+  }
+
+  impl Attributes {
+    pub fn empty() -> Attributes {
+      Attributes { bits: 0 }
+    }
+
+    pub fn contains(&self, other: Attributes) -> bool {
+      (self.bits & other.bits) == other.bits
+    }
+  }
+
+  impl BitOr<Attributes, Attributes> for Attributes {
+    fn bitor(&self, rhs: &Attributes) -> Attributes {
+      Attributes { bits: self.bits | rhs.bits }
+    }
+  }
+
+  pub const BOLD: Attributes = Attributes { bits: 0x01 };
+  pub const ITALIC: Attributes = Attributes { bits: 0x02 };
+  pub const UNDERLINE: Attributes = Attributes { bits: 0x04 };
+  pub const REVERSE: Attributes = Attributes { bits: 0x08 };
+  pub const STRIKETHROUGH: Attributes = Attributes { bits: 0x10 };
 }
 
 /*